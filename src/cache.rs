@@ -0,0 +1,93 @@
+//! In-RAM caching of page state, to cut down on the flash reads `find_recovery` and repeated
+//! `read`/`check` passes would otherwise perform.
+//!
+//! On a constrained device, keeping one entry per block in RAM (`PageStateCache`) is what makes
+//! `find_recovery`'s scan robust, instead of relying on the assumption that re-reading the same
+//! flash page twice always returns the same thing.
+//! `NoCache` is the zero-cost default for when that RAM isn't available.
+
+use std::collections::HashMap;
+
+use crate::flash::PageState;
+use crate::PageLocation;
+
+/// What a cache remembers about a single page: its state, and (once it has been read) its
+/// contents.  Digests and pattern checks can both be derived from the payload, so that's the
+/// only thing worth keeping around.
+#[derive(Debug, Clone)]
+pub(crate) struct PageEntry {
+    pub(crate) state: PageState,
+    pub(crate) payload: Option<Vec<u8>>,
+}
+
+/// A cache of per-page state read during a recovery scan.
+pub(crate) trait Cache {
+    /// Return a previously recorded entry for `loc`, if this cache still has one.
+    fn lookup(&mut self, loc: &PageLocation) -> Option<PageEntry>;
+
+    /// Record an entry that was just read from flash for `loc`.
+    fn record(&mut self, loc: &PageLocation, entry: PageEntry);
+
+    /// Drop any entry recorded for `loc`.  Must be called after every erase or write, since the
+    /// page's contents (and therefore its state) may have changed.
+    fn invalidate(&mut self, loc: &PageLocation);
+
+    /// How many real flash reads have gone through this cache so far.
+    fn reads(&self) -> usize;
+}
+
+/// Caches nothing: every `lookup` misses, so every page is re-read from flash.  This is the
+/// zero-cost default for devices without the RAM to spare.
+#[derive(Debug, Default)]
+pub(crate) struct NoCache {
+    reads: usize,
+}
+
+impl Cache for NoCache {
+    fn lookup(&mut self, _loc: &PageLocation) -> Option<PageEntry> {
+        None
+    }
+
+    fn record(&mut self, _loc: &PageLocation, _entry: PageEntry) {
+        self.reads += 1;
+    }
+
+    fn invalidate(&mut self, _loc: &PageLocation) {}
+
+    fn reads(&self) -> usize {
+        self.reads
+    }
+}
+
+/// Keeps every page entry it has seen in RAM, so a page is only ever read from flash once
+/// between invalidations.
+#[derive(Debug, Default)]
+pub(crate) struct PageStateCache {
+    entries: HashMap<(usize, usize), PageEntry>,
+    reads: usize,
+}
+
+impl PageStateCache {
+    pub(crate) fn new() -> PageStateCache {
+        PageStateCache::default()
+    }
+}
+
+impl Cache for PageStateCache {
+    fn lookup(&mut self, loc: &PageLocation) -> Option<PageEntry> {
+        self.entries.get(&(loc.slot, loc.index)).cloned()
+    }
+
+    fn record(&mut self, loc: &PageLocation, entry: PageEntry) {
+        self.reads += 1;
+        self.entries.insert((loc.slot, loc.index), entry);
+    }
+
+    fn invalidate(&mut self, loc: &PageLocation) {
+        self.entries.remove(&(loc.slot, loc.index));
+    }
+
+    fn reads(&self) -> usize {
+        self.reads
+    }
+}
@@ -11,39 +11,230 @@
 //! units.  The "page" will correspond to erases in either of these, but the small-write will
 //! enable some optimizations where status can be written incrementally in the last page instead of
 //! requiring its own page(s).
+//!
+//! To capture the NOR case faithfully, writes are modeled word-by-word (see `Geometry::write_size`):
+//! a write may only ever clear bits, never set one back to a 1, and (other than the fully-erased
+//! case) a word may only be written once or twice since its last erase, matching how real NOR
+//! parts behave and how mock flashes validate "double writes".
+//!
+//! Geometry (page size, write granularity, erase value) is a runtime property of each `Slot`
+//! rather than a compile-time constant, so a single process can model two partitions backed by
+//! different flash devices, such as an active image on internal NOR and a DFU staging area on an
+//! external part with a different write granularity.
 
-use crate::Result;
+use crate::cache::{Cache, PageEntry};
+use crate::{PageLocation, Result};
 use anyhow::anyhow;
+use sha2::{Digest, Sha256};
 use std::io::Write;
 
-/// For this prototype, we will make the page size a compile-time constant.  This can be abstracted
-/// later, if this code is ever used in a real device.
-pub const PAGE_SIZE: usize = 32;
+/// A flash device's physical shape: how big its erase unit is, what a byte settles to once
+/// erased, and the smallest unit it can independently write.  On-flash sizes are `u32` since real
+/// parts (even 16-bit MCUs) can have more flash than fits a 16-bit address; in-memory buffers are
+/// still indexed with `usize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// The largest-smallest unit that can be erased, in bytes.
+    pub page_size: u32,
+    /// The byte value flash reads back as once a page has been erased.
+    pub erase_value: u8,
+    /// The smallest unit that can be independently written.  Every write must be aligned to, and
+    /// a whole multiple of, this many bytes.
+    pub write_size: u32,
+    /// How strictly repeated writes to the same word are enforced.  Defaults to `Enforced`;
+    /// use `with_write_mode` to select `Disabled` for devices that don't need the check.
+    pub write_mode: WriteCountCheck,
+}
+
+impl Geometry {
+    pub const fn new(page_size: u32, erase_value: u8, write_size: u32) -> Geometry {
+        Geometry {
+            page_size,
+            erase_value,
+            write_size,
+            write_mode: WriteCountCheck::Enforced,
+        }
+    }
+
+    /// Select how strictly repeated writes to the same word are enforced.
+    pub const fn with_write_mode(mut self, write_mode: WriteCountCheck) -> Geometry {
+        self.write_mode = write_mode;
+        self
+    }
+}
+
+/// The geometry used by the demo/fuzz harnesses when nothing more specific is needed.
+pub const DEFAULT_GEOMETRY: Geometry = Geometry::new(32, 0xFF, 4);
+
+/// How strictly repeated writes to the same word (between erases) are enforced.
+///
+/// Real NOR flash can only clear bits, never set them, so writing the same word twice is safe
+/// as long as the second write is a pure narrowing of the first.  Borrowed from how mock flash
+/// simulators validate this, since it's exactly the kind of bug ("double write") that's easy to
+/// introduce in swap/status logic and easy to miss without enforcement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteCountCheck {
+    /// Don't track or enforce a limit on repeated writes.
+    Disabled,
+    /// Allow at most two writes to a word since its last erase, and only if the second is a
+    /// pure narrowing (no attempt to set a cleared bit) of the first.
+    Enforced,
+}
 
 /// The flash consists of a number of pages of data.  In this usage, we will treat each partition
 /// as just a different flash device.
 #[derive(Debug)]
 pub struct Slot {
-    data: Vec<Page>,
+    pub(crate) index: usize,
+    pub(crate) geometry: Geometry,
+    pub(crate) data: Vec<Page>,
 }
 
 impl Slot {
-    pub fn new(pages: usize) -> Slot {
-        let data: Vec<_> = (0..pages).map(|_p| Page::new()).collect();
-        Slot { data }
+    pub fn new(index: usize, pages: usize, geometry: Geometry) -> Slot {
+        let data: Vec<_> = (0..pages).map(|_p| Page::new(geometry)).collect();
+        Slot {
+            index,
+            geometry,
+            data,
+        }
+    }
+
+    /// This slot's geometry, as given to `Slot::new`.
+    pub(crate) fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Convenience accessor: this slot's page size, as a buffer-indexable `usize`.
+    pub(crate) fn page_size(&self) -> usize {
+        self.geometry.page_size as usize
+    }
+
+    /// Read page `index`'s contents, consulting `cache` first so a page already seen since its
+    /// last erase/write doesn't cost another flash read.
+    fn cached_read(&self, index: usize, cache: &mut impl Cache) -> Result<Vec<u8>> {
+        let key = PageLocation {
+            slot: self.index,
+            index,
+        };
+        if let Some(entry) = cache.lookup(&key) {
+            if entry.state != PageState::Written {
+                return Err(anyhow!("Read from invalid state: {:?}", entry.state));
+            }
+            if let Some(payload) = entry.payload {
+                return Ok(payload);
+            }
+        }
+
+        let mut buf = vec![0u8; self.page_size()];
+        self.data[index].read(&mut buf)?;
+        cache.record(
+            &key,
+            PageEntry {
+                state: self.data[index].state(),
+                payload: Some(buf.clone()),
+            },
+        );
+        Ok(buf)
+    }
+
+    /// Like `Page::digest`, but consults `cache` first.
+    pub(crate) fn digest(
+        &self,
+        index: usize,
+        loc: &PageLocation,
+        cache: &mut impl Cache,
+    ) -> Result<Vec<u8>> {
+        let buf = self.cached_read(index, cache)?;
+        Ok(Page::hash(&buf, loc))
+    }
+
+    /// Like `Page::check`, but consults `cache` first.
+    pub(crate) fn check(
+        &self,
+        index: usize,
+        loc: &PageLocation,
+        cache: &mut impl Cache,
+    ) -> Result<()> {
+        let buf = self.cached_read(index, cache)?;
+        Page::check_pattern(&buf, self.geometry.erase_value, loc.slot, loc.index)
+    }
+
+    /// Plain, uncached read of page `index`.  Used by the swap itself, which only ever reads
+    /// each page once per pass.
+    pub(crate) fn read(&self, index: usize, buffer: &mut [u8]) -> Result<()> {
+        self.data[index].read(buffer)
+    }
+
+    /// Read page `index` without regard to its state.  An escape hatch for callers (such as a
+    /// recovery scan) that need to see whatever bytes are there even while the page is
+    /// indeterminate.
+    pub(crate) fn read_whatever(&self, index: usize, buffer: &mut [u8]) -> Result<()> {
+        self.data[index].read_whatever(buffer)
+    }
+
+    /// Erase page `index`, invalidating any cache entry for it since its contents have changed.
+    pub(crate) fn erase(&mut self, index: usize, cache: &mut impl Cache) -> Result<()> {
+        let result = self.data[index].erase();
+        cache.invalidate(&PageLocation {
+            slot: self.index,
+            index,
+        });
+        result
+    }
+
+    /// Write `buffer` to page `index`, invalidating any cache entry for it.
+    pub(crate) fn write(
+        &mut self,
+        index: usize,
+        buffer: &[u8],
+        cache: &mut impl Cache,
+    ) -> Result<()> {
+        let result = self.data[index].write(buffer);
+        cache.invalidate(&PageLocation {
+            slot: self.index,
+            index,
+        });
+        result
+    }
+
+    /// Partially erase page `index` (simulating an interrupted erase), invalidating its cache
+    /// entry since its state has changed.
+    pub(crate) fn partial_erase(&mut self, index: usize, cache: &mut impl Cache) {
+        self.data[index].partial_erase();
+        cache.invalidate(&PageLocation {
+            slot: self.index,
+            index,
+        });
+    }
+
+    /// Partially write `buffer` to page `index` (simulating an interrupted write), invalidating
+    /// its cache entry since its state has changed.
+    pub(crate) fn partial_write(&mut self, index: usize, buffer: &[u8], cache: &mut impl Cache) {
+        self.data[index].partial_write(buffer);
+        cache.invalidate(&PageLocation {
+            slot: self.index,
+            index,
+        });
     }
 }
 
 /// A page itself is some amount of data.
 #[derive(Debug)]
 pub struct Page {
+    geometry: Geometry,
     payload: Vec<u8>,
     pstate: PageState,
+
+    /// Number of times each `geometry.write_size` word has been written since its last erase.
+    write_counts: Vec<u8>,
+
+    write_mode: WriteCountCheck,
 }
 
 /// The state of a given page.
-#[derive(Debug, Eq, PartialEq)]
-enum PageState {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum PageState {
     Written,
     Erased,
     PartiallyWritten,
@@ -53,27 +244,29 @@ enum PageState {
 impl Page {
     /// Construct a new, empty page.  It is erased, but set as partially erased to ensure actual
     /// erases happen before it is used.
-    fn new() -> Page {
-        let buf = vec![0xFFu8; PAGE_SIZE];
+    fn new(geometry: Geometry) -> Page {
+        let buf = vec![geometry.erase_value; geometry.page_size as usize];
         Page {
+            geometry,
             payload: buf,
             pstate: PageState::PartiallyErased,
+            write_counts: vec![0; geometry.page_size as usize / geometry.write_size as usize],
+            write_mode: geometry.write_mode,
         }
     }
 
-    /// A utility, to fill a page buffer with the expected data for a page.
-    fn fill(buf: &mut [u8], slot: usize, index: usize) {
-        assert_eq!(buf.len(), PAGE_SIZE, "Page size is not correct");
-        buf.fill(0xFF);
+    /// A utility, to fill a page buffer with the expected data for a page.  `buf`'s length is
+    /// taken as the page size; `erase_value` is the byte the rest of the page should carry.
+    pub(crate) fn fill(buf: &mut [u8], erase_value: u8, slot: usize, index: usize) {
+        buf.fill(erase_value);
         let mut writer: &mut [u8] = buf;
         write!(writer, "Slot {}, page {}, data", slot, index).unwrap();
     }
 
-    /// Check a filled page.
-    fn check(buf: &[u8], slot: usize, index: usize) -> Result<()> {
-        assert_eq!(buf.len(), PAGE_SIZE, "Page size is not correct");
-        let mut tmp = vec![0xFFu8; PAGE_SIZE];
-        Self::fill(&mut tmp, slot, index);
+    /// Check a filled buffer against the pattern expected for a page.
+    pub(crate) fn check_pattern(buf: &[u8], erase_value: u8, slot: usize, index: usize) -> Result<()> {
+        let mut tmp = vec![erase_value; buf.len()];
+        Self::fill(&mut tmp, erase_value, slot, index);
         if buf == tmp {
             Ok(())
         } else {
@@ -81,9 +274,16 @@ impl Page {
         }
     }
 
+    /// Read this page and check it against the pattern expected for `loc`.
+    pub(crate) fn check(&self, loc: &PageLocation) -> Result<()> {
+        let mut buf = vec![0u8; self.payload.len()];
+        self.read(&mut buf)?;
+        Self::check_pattern(&buf, self.geometry.erase_value, loc.slot, loc.index)
+    }
+
     /// Normal read from the page.  If the page is not in a state where this makes sense, it will
     /// return an error.
-    fn read(&self, buffer: &mut [u8]) -> Result<()> {
+    pub(crate) fn read(&self, buffer: &mut [u8]) -> Result<()> {
         match self.pstate {
             PageState::Written => {
                 buffer.copy_from_slice(&self.payload);
@@ -93,46 +293,140 @@ impl Page {
         }
     }
 
+    /// This page's current state, for callers (such as the page cache) that need to know
+    /// whether a read would succeed without actually performing one.
+    pub(crate) fn state(&self) -> PageState {
+        self.pstate
+    }
+
     /// A safe read from the page.  Reads from flash without regard to the state.  Nothing should
     /// depend on the value read here, but is needed when we don't know where an operation left
     /// off.
-    fn read_whatever(&self, buffer: &mut [u8]) -> Result<()> {
+    pub(crate) fn read_whatever(&self, buffer: &mut [u8]) -> Result<()> {
         buffer.copy_from_slice(&self.payload);
         Ok(())
     }
 
     /// Erase the contents of the page.
-    fn erase(&mut self) -> Result<()> {
+    pub(crate) fn erase(&mut self) -> Result<()> {
         self.pstate = PageState::Erased;
-        self.payload.fill(0xFF);
+        self.payload.fill(self.geometry.erase_value);
+        self.write_counts.fill(0);
         Ok(())
     }
 
     /// Partial erase.  We make no changes to the data, acting as if we are at the very beginning
     /// of the operation.
-    fn partial_erase(&mut self) {
+    pub(crate) fn partial_erase(&mut self) {
         self.pstate = PageState::PartiallyErased;
     }
 
-    /// Write new contents to the page.  Will error if the page isn't in the erased state.
-    fn write(&mut self, buffer: &[u8]) -> Result<()> {
-        if let PageState::Erased = self.pstate {
-            self.payload.copy_from_slice(buffer);
-            self.pstate = PageState::Written;
-            Ok(())
-        } else {
-            Err(anyhow!(
+    /// Write new contents to the page.  Will error if the page isn't in the erased state, or if
+    /// it would require setting a bit flash can only ever clear.  Panics if `buffer` isn't
+    /// exactly one page, or the page isn't a whole number of `geometry.write_size` words.
+    pub(crate) fn write(&mut self, buffer: &[u8]) -> Result<()> {
+        if self.pstate != PageState::Erased {
+            return Err(anyhow!(
                 "Attempt to write to unerased page {:?}",
                 self.pstate
-            ))
+            ));
+        }
+        assert_eq!(buffer.len(), self.payload.len(), "Page size is not correct");
+        self.write_words(0, buffer)?;
+        self.pstate = PageState::Written;
+        Ok(())
+    }
+
+    /// Write only a (word-aligned) prefix of `buffer`, leaving the rest of the page untouched.
+    /// Models a write interrupted partway through: the page is left in the `PartiallyWritten`
+    /// state, which `read` will refuse, faithfully simulating a crash mid-write.
+    pub(crate) fn partial_write(&mut self, buffer: &[u8]) {
+        if self.pstate != PageState::Erased {
+            return;
+        }
+        assert_eq!(buffer.len(), self.payload.len(), "Page size is not correct");
+
+        let write_size = self.geometry.write_size as usize;
+        let words = buffer.len() / write_size;
+        let prefix = (words / 2) * write_size;
+        let _ = self.write_words(0, &buffer[..prefix]);
+        self.pstate = PageState::PartiallyWritten;
+    }
+
+    /// Commit `buf` (a whole number of `geometry.write_size` words) starting at word
+    /// `start_word`, enforcing NOR write semantics: each written byte may only clear bits
+    /// relative to what is already there, and (when `write_mode` is `Enforced`) a word may be
+    /// written at most twice since its last erase.
+    fn write_words(&mut self, start_word: usize, buf: &[u8]) -> Result<()> {
+        let write_size = self.geometry.write_size as usize;
+        assert_eq!(
+            buf.len() % write_size,
+            0,
+            "writes must be a whole number of words"
+        );
+
+        for (i, chunk) in buf.chunks(write_size).enumerate() {
+            let w = start_word + i;
+            let offset = w * write_size;
+            let old = self.payload[offset..offset + chunk.len()].to_vec();
+
+            if self.write_mode == WriteCountCheck::Enforced && self.write_counts[w] >= 2 {
+                return Err(anyhow!(
+                    "word {} written more than twice since last erase",
+                    w
+                ));
+            }
+
+            for (&o, &n) in old.iter().zip(chunk.iter()) {
+                if n & !o != 0 {
+                    return Err(anyhow!(
+                        "write would set a bit flash can only clear (word {})",
+                        w
+                    ));
+                }
+            }
+
+            for (dst, (&o, &n)) in self.payload[offset..offset + chunk.len()]
+                .iter_mut()
+                .zip(old.iter().zip(chunk.iter()))
+            {
+                *dst = o & n;
+            }
+            self.write_counts[w] += 1;
         }
+        Ok(())
+    }
+
+    /// This page's raw contents, regardless of state.  Used for parity computation, which needs
+    /// to see every page's bytes.
+    pub(crate) fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Compute a digest of this page's current contents, bound to its location so that pages
+    /// can't be silently transposed.  Requires the page to be in the `Written` state; use this
+    /// to build or check against a `MerkleTree`.
+    pub(crate) fn digest(&self, loc: &PageLocation) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.payload.len()];
+        self.read(&mut buf)?;
+        Ok(Self::hash(&buf, loc))
+    }
+
+    /// Hash an already-read page buffer, bound to `loc`.  Split out from `digest` so a cache
+    /// that already has the buffer in RAM can hash it without re-reading the page.
+    pub(crate) fn hash(buf: &[u8], loc: &PageLocation) -> Vec<u8> {
+        let mut state = Sha256::new();
+        state.update((loc.slot as u32).to_le_bytes());
+        state.update((loc.index as u32).to_le_bytes());
+        state.update(buf);
+        state.finalize().to_vec()
     }
 }
 
 #[test]
 fn test_flash_basics() {
-    let mut fl = Slot::new(10);
-    let mut buf = vec![0u8; PAGE_SIZE];
+    let mut fl = Slot::new(0, 10, DEFAULT_GEOMETRY);
+    let mut buf = vec![0u8; fl.page_size()];
 
     // Ensure that these pages are all in a weird erased state.
     for p in 0..fl.data.len() {
@@ -154,7 +448,7 @@ fn test_flash_basics() {
     // The data should appear erased.
     assert!(buf.iter().all(|&b| b == 0xFF));
 
-    Page::fill(&mut buf, 5, 7);
+    Page::fill(&mut buf, 0xFF, 5, 7);
 
     // Write the pattern to the erased page.
     assert!(matches!(fl.data[1].write(&buf), Ok(())));
@@ -164,5 +458,32 @@ fn test_flash_basics() {
     // Read it back.
     assert!(matches!(fl.data[1].read(&mut buf), Ok(())));
 
-    assert!(matches!(Page::check(&buf, 5, 7), Ok(())));
+    assert!(matches!(Page::check_pattern(&buf, 0xFF, 5, 7), Ok(())));
+}
+
+#[test]
+fn test_write_count_enforced() {
+    let mut fl = Slot::new(0, 2, DEFAULT_GEOMETRY);
+    let page = &mut fl.data[0];
+    page.erase().unwrap();
+
+    // First write to the word: fine.
+    page.write_words(0, &[0x0F, 0x0F, 0x0F, 0x0F]).unwrap();
+    // Second write, only narrowing further: still fine, this is the "double write" NOR allows.
+    page.write_words(0, &[0x03, 0x03, 0x03, 0x03]).unwrap();
+    // A third write to the same word is never allowed, even if it would only narrow further.
+    assert!(page.write_words(0, &[0x01, 0x01, 0x01, 0x01]).is_err());
+}
+
+#[test]
+fn test_write_cannot_set_bits() {
+    let mut fl = Slot::new(0, 2, DEFAULT_GEOMETRY);
+    let page = &mut fl.data[0];
+    page.erase().unwrap();
+
+    // Clear the low nibble of the word.
+    page.write_words(0, &[0x0F, 0x0F, 0x0F, 0x0F]).unwrap();
+    // Attempting to set a bit that's already been cleared (the high nibble is gone) is rejected,
+    // even though this is only the word's second write.
+    assert!(page.write_words(0, &[0xFF, 0xFF, 0xFF, 0xFF]).is_err());
 }
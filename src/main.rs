@@ -14,38 +14,40 @@
 // Turn this off once more code is written.
 #![allow(dead_code)]
 
-// use sha2::{Digest, Sha256};
-// use std::io::Write;
+use rand::Rng;
+use sha2::{Digest, Sha256};
 
-use flash::{Flash};
+use cache::{Cache, PageStateCache};
+use flash::{Geometry, Page, Slot, DEFAULT_GEOMETRY};
 
+mod cache;
 mod flash;
+mod fuzz;
+mod norflash;
 mod pdump;
 
 type Result<T> = anyhow::Result<T>;
 
 fn main() -> Result<()> {
-    let flash = Flash::build([16, 16], [14, 13])?;
-    let _ = flash;
-    println!("flash: {}", flash);
-    // recovery(0)?;
+    recovery(0)?;
     Ok(())
 }
 
-/*
 /// Perform a swap with the given stopping point, and attempt recovery.
 fn recovery(stop: usize) -> Result<()> {
-    let mut work = Status::new(6)?;
+    let mut cache = PageStateCache::new();
+    let mut work = Status::new(6, DEFAULT_GEOMETRY, 6, DEFAULT_GEOMETRY, &mut cache)?;
 
     work.stop = Some(stop);
-    if let SwapResult::Finished = work.swap() {
+    if let SwapResult::Finished = work.swap(&mut cache) {
         panic!("Too many steps for work to complete");
     }
 
     // TODO: Allow for multiple stopping points.
     work.stop = None;
-    work.recover()?;
-    work.final_check();
+    work.recover(&mut cache)?;
+    work.final_check(&mut cache)?;
+    println!("page cache reads: {}", cache.reads());
     Ok(())
 }
 
@@ -55,18 +57,77 @@ enum SwapResult {
     Interrupted,
 }
 
-#[derive(Debug)]
-struct PageLocation {
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PageLocation {
     slot: usize,
     index: usize,
 }
 
+/// A Merkle-style tree of per-page digests, computed over a slot's image before the swap
+/// touches anything.  Used during recovery to confirm, page by page, whether a given page
+/// already holds its post-swap content, without needing to re-read the whole image.
+#[derive(Debug)]
+struct MerkleTree {
+    leaves: Vec<Vec<u8>>,
+    root: Vec<u8>,
+}
+
+impl MerkleTree {
+    /// Build the tree over the first `count` pages of `slot`'s current contents.  `count` is the
+    /// number of pages the swap will actually touch, which may be fewer than `slot` holds if the
+    /// other slot in the pair has fewer pages.
+    fn build(slot: &Slot, count: usize, cache: &mut impl Cache) -> Result<MerkleTree> {
+        let mut leaves = Vec::with_capacity(count);
+        for index in 0..count {
+            let loc = PageLocation {
+                slot: slot.index,
+                index,
+            };
+            leaves.push(slot.digest(index, &loc, cache)?);
+        }
+
+        let mut state = Sha256::new();
+        for leaf in &leaves {
+            state.update(leaf);
+        }
+        let root = state.finalize().to_vec();
+
+        Ok(MerkleTree { leaves, root })
+    }
+
+    /// The digest expected for the page at `index`.
+    fn leaf(&self, index: usize) -> &[u8] {
+        &self.leaves[index]
+    }
+
+    /// The overall root, combining every leaf.
+    fn root(&self) -> &[u8] {
+        &self.root
+    }
+}
+
 /// For this experiment, we don't try to map the status into the flash itself, but merely store it
 /// in memory.
 #[derive(Debug)]
 struct Status {
     slots: [Slot; 2],
-    root: Vec<u8>,
+
+    /// How many pages the swap pairs up between the two slots: the lesser of the two slots'
+    /// page counts.  Any pages beyond this in the larger slot sit outside the swap entirely.
+    paired_pages: usize,
+
+    /// Per-page digests of slot 1's original (pre-swap) image, which is exactly what slot 0 is
+    /// expected to hold once the swap completes.
+    tree: MerkleTree,
+
+    /// Per-page digests of slot 0's original (pre-swap) image, which is exactly what slot 1 is
+    /// expected to hold once the swap completes.  Checking by digest against this (rather than
+    /// recomputing `Page::fill`'s fixed pattern) is what lets page contents be arbitrary, e.g.
+    /// the random bytes `new_random` seeds slots with.
+    tree0: MerkleTree,
+
+    /// XOR of slot 0's original (pre-swap) paired pages.  Captured before any erase, so
+    /// it can reconstruct whichever single page a crash leaves indeterminate.
     parity: Vec<u8>,
 
     /// What step in the swap process are we on.
@@ -79,57 +140,76 @@ struct Status {
     resume: Option<PageLocation>,
 }
 
-// Nice display for Page.
-/*
-impl fmt::Display for Page {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(pos) = self.payload.iter().position(|&x| x == 0xFF) {
-            write!(f, "[{}]", str::from_utf8(&self.payload[0..pos]).unwrap())?;
-        } else {
-            write!(f, "[unknown page]")?;
-        }
-        Ok(())
-    }
-}
-*/
-
-impl Slot {
-    /// Compute the Merkel root for the data in the slot.
-    /// TODO: Don't return and copy result, twice
-    fn compute_root(&self) -> Result<Vec<u8>> {
-        let mut state = Sha256::new();
-        for (index, b) in self.data.iter().enumerate() {
-            state.update(&b.digest(PageLocation {
-                slot: self.index,
-                index,
-            })?);
-        }
-        Ok(state.finalize().to_vec())
+impl Status {
+    /// Build a pair of slots and the bookkeeping needed to swap between them.  `size0`/`geometry0`
+    /// and `size1`/`geometry1` describe each slot independently, since they may be backed by
+    /// different flash devices; the two geometries must still agree on `page_size`, since the
+    /// swap pairs up pages between the slots one-for-one.
+    fn new(
+        size0: usize,
+        geometry0: Geometry,
+        size1: usize,
+        geometry1: Geometry,
+        cache: &mut impl Cache,
+    ) -> Result<Status> {
+        Self::new_with_fill(size0, geometry0, size1, geometry1, cache, |buf, erase_value, slot, index| {
+            Page::fill(buf, erase_value, slot, index);
+        })
     }
 
-    /// Compute a parity block for the entire image.
-    fn compute_parity(&self) -> Vec<u8> {
-        let mut result = vec![0u8; PAGE_SIZE];
-
-        for b in &self.data {
-            for (i, &bt) in b.payload.iter().enumerate() {
-                result[i] ^= bt;
+    /// Like `new`, but seeds every page with random bytes from `rng` instead of `Page::fill`'s
+    /// fixed pattern.  Used by the fuzz harness so it also exercises content-dependent edge cases
+    /// (arbitrary bit patterns flowing through the AND-masked write model and the XOR parity),
+    /// not just varying geometry.
+    fn new_random(
+        size0: usize,
+        geometry0: Geometry,
+        size1: usize,
+        geometry1: Geometry,
+        cache: &mut impl Cache,
+        rng: &mut impl Rng,
+    ) -> Result<Status> {
+        Self::new_with_fill(size0, geometry0, size1, geometry1, cache, |buf, _erase_value, _slot, _index| {
+            for byte in buf.iter_mut() {
+                *byte = rng.gen();
             }
-        }
-        result
+        })
     }
-}
 
-impl Status {
-    fn new(size: usize) -> Result<Status> {
-        let slot0 = Slot::new(0, size);
-        let slot1 = Slot::new(1, size);
-        let root = slot1.compute_root()?;
-        let parity = slot0.compute_parity();
+    /// Shared setup for `new`/`new_random`: build a pair of slots, seed every page via `fill`,
+    /// then capture the digests and parity needed for recovery.  `size0`/`geometry0` and
+    /// `size1`/`geometry1` describe each slot independently, since they may be backed by
+    /// different flash devices; the two geometries must still agree on `page_size`, since the
+    /// swap pairs up pages between the slots one-for-one.
+    fn new_with_fill(
+        size0: usize,
+        geometry0: Geometry,
+        size1: usize,
+        geometry1: Geometry,
+        cache: &mut impl Cache,
+        mut fill: impl FnMut(&mut [u8], u8, usize, usize),
+    ) -> Result<Status> {
+        assert_eq!(
+            geometry0.page_size, geometry1.page_size,
+            "Paired slots must share a page size"
+        );
+
+        let mut slot0 = Slot::new(0, size0, geometry0);
+        let mut slot1 = Slot::new(1, size1, geometry1);
+        Self::init_slot(&mut slot0, cache, &mut fill)?;
+        Self::init_slot(&mut slot1, cache, &mut fill)?;
+
+        let paired_pages = slot0.data.len().min(slot1.data.len());
+
+        let tree = MerkleTree::build(&slot1, paired_pages, cache)?;
+        let tree0 = MerkleTree::build(&slot0, paired_pages, cache)?;
+        let parity = Self::compute_parity(&slot0, paired_pages);
 
         Ok(Status {
             slots: [slot0, slot1],
-            root,
+            paired_pages,
+            tree,
+            tree0,
             parity,
             step: 0,
             stop: None,
@@ -137,108 +217,230 @@ impl Status {
         })
     }
 
-    fn swap(&mut self) -> SwapResult {
-        // TODO: Support different sizes for the slots.
-        assert_eq!(self.slots[0].data.len(), self.slots[1].data.len());
+    /// Seed a freshly constructed slot with its initial contents, as produced by `fill` for each
+    /// page in turn.
+    fn init_slot(
+        slot: &mut Slot,
+        cache: &mut impl Cache,
+        fill: &mut impl FnMut(&mut [u8], u8, usize, usize),
+    ) -> Result<()> {
+        let geometry = slot.geometry();
+        let mut buf = vec![0u8; geometry.page_size as usize];
+        for index in 0..slot.data.len() {
+            fill(&mut buf, geometry.erase_value, slot.index, index);
+            slot.erase(index, cache)?;
+            slot.write(index, &buf, cache)?;
+        }
+        Ok(())
+    }
 
-        // We need two buffers for the operation.
-        let mut abuf = vec![0u8; PAGE_SIZE];
-        let mut bbuf = vec![0u8; PAGE_SIZE];
-
-        for sec in 0..self.slots[0].data.len() {
-            // We need to re-borrow this value each time we access the field.  This macro helps
-            // keep the reference short.
-            macro_rules! slot {
-                ($index:literal) => {
-                    self.slots[$index].data[sec]
-                };
+    /// Compute a parity block (XOR of the first `count` pages) for the slot's current image.
+    fn compute_parity(slot: &Slot, count: usize) -> Vec<u8> {
+        let mut result = vec![0u8; slot.page_size()];
+        for page in &slot.data[..count] {
+            for (i, &b) in page.payload().iter().enumerate() {
+                result[i] ^= b;
             }
+        }
+        result
+    }
+
+    fn swap(&mut self, cache: &mut impl Cache) -> SwapResult {
+        self.swap_from(0, cache)
+    }
 
-            slot!(0).read(&mut abuf);
-            slot!(1).read(&mut bbuf);
+    /// Run the page-by-page swap, starting at section `start`.  Used both for a fresh swap
+    /// (`start == 0`) and to resume one after recovery has repaired the indeterminate page.  Only
+    /// the paired pages are touched; any pages beyond that in the larger slot are left alone.
+    fn swap_from(&mut self, start: usize, cache: &mut impl Cache) -> SwapResult {
+        // We need two buffers for the operation.
+        let mut abuf = vec![0u8; self.slots[0].page_size()];
+        let mut bbuf = vec![0u8; self.slots[1].page_size()];
+
+        for sec in start..self.paired_pages {
+            let _ = self.slots[0].read(sec, &mut abuf);
+            let _ = self.slots[1].read(sec, &mut bbuf);
 
             // We consume 4 steps here.  One is before the erase, one after the write, and in both
             // cases, we make sure that we restart after the write.
 
             self.step += 1;
             if self.is_stop() {
-                slot!(0).partial_erase();
+                self.slots[0].partial_erase(sec, cache);
                 self.resume = Some(PageLocation {
                     slot: 0,
                     index: sec,
                 });
                 return SwapResult::Interrupted;
             } else {
-                slot!(0).erase();
+                let _ = self.slots[0].erase(sec, cache);
             }
 
             self.step += 1;
             if self.is_stop() {
-                slot!(0).partial_write(&bbuf);
+                self.slots[0].partial_write(sec, &bbuf, cache);
                 self.resume = Some(PageLocation {
                     slot: 0,
                     index: sec,
                 });
                 return SwapResult::Interrupted;
             } else {
-                slot!(0).write(&bbuf);
+                let _ = self.slots[0].write(sec, &bbuf, cache);
             }
 
             self.step += 1;
             if self.is_stop() {
-                slot!(1).partial_erase();
+                self.slots[1].partial_erase(sec, cache);
                 self.resume = Some(PageLocation {
                     slot: 1,
                     index: sec,
                 });
                 return SwapResult::Interrupted;
             } else {
-                slot!(1).erase();
+                let _ = self.slots[1].erase(sec, cache);
             }
 
             self.step += 1;
             if self.is_stop() {
-                slot!(1).partial_write(&abuf);
+                self.slots[1].partial_write(sec, &abuf, cache);
                 self.resume = Some(PageLocation {
                     slot: 1,
                     index: sec,
                 });
                 return SwapResult::Interrupted;
             } else {
-                slot!(1).write(&abuf);
+                let _ = self.slots[1].write(sec, &abuf, cache);
             }
         }
 
         SwapResult::Finished
     }
 
-    /// Perform a startup recovery.  Finds the recovery point, and continues the swapping.
-    fn recover(&mut self) -> Result<()> {
-        let loc = self.find_recovery()?;
+    /// Perform a startup recovery.  Finds the recovery point, repairs the one indeterminate
+    /// page (if any) using parity, and continues the swap from there.
+    fn recover(&mut self, cache: &mut impl Cache) -> Result<()> {
+        let loc = self.find_recovery(cache)?;
         println!("loc: {:?}", loc);
+
+        if loc.index < self.paired_pages {
+            self.repair_page(loc.index, cache)?;
+            self.swap_from(loc.index + 1, cache);
+        }
+
+        self.stop = None;
+        self.resume = None;
         Ok(())
     }
 
-    /// Scan the device for the recovery point.  If we have enough RAM for
-    /// hashes for every block, we can be a little more robust, not having to
-    /// rely on the possibility of consecutive reads of the same data returning
-    /// something different.
-    fn find_recovery(&self) -> Result<PageLocation> {
-        unimplemented!()
+    /// Scan the device for the recovery point.  Because the swap processes pages strictly in
+    /// order, "is this page done" is monotonic across the image: binary-search for the boundary
+    /// instead of scanning every page, giving O(log n) reads rather than O(n).  Passing a
+    /// `PageStateCache` makes this robust against re-reading the same page twice during the
+    /// scan, since digests are computed from its cached page contents instead of relying on
+    /// consecutive flash reads returning the same thing.
+    fn find_recovery(&self, cache: &mut impl Cache) -> Result<PageLocation> {
+        let n = self.paired_pages;
+
+        let mut lo = 0;
+        let mut hi = n;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.page_done(mid, cache) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        Ok(PageLocation { slot: 0, index: lo })
+    }
+
+    /// Has page `index` been fully swapped in both directions?  Tolerates pages left in an
+    /// indeterminate state by a crash; those simply fail to match and count as not done.
+    fn page_done(&self, index: usize, cache: &mut impl Cache) -> bool {
+        // The tree's leaves were built over slot 1's original image (`MerkleTree::build` hashes
+        // with `loc.slot = slot.index == 1`), so rehashing slot 0's current page must use that
+        // same domain to land on the same digest.
+        let tree_loc = PageLocation { slot: 1, index };
+        let slot0_ok = self.slots[0]
+            .digest(index, &tree_loc, cache)
+            .map(|d| d == self.tree.leaf(index))
+            .unwrap_or(false);
+        if !slot0_ok {
+            return false;
+        }
+
+        // Slot 1's "new" content is exactly slot 0's original content, which lives in the
+        // `loc.slot == 0` domain `tree0` was built over.
+        let orig_loc = PageLocation { slot: 0, index };
+        self.slots[1]
+            .digest(index, &orig_loc, cache)
+            .map(|d| d == self.tree0.leaf(index))
+            .unwrap_or(false)
+    }
+
+    /// Repair the single page left indeterminate at `index`, restoring both slots to a clean,
+    /// fully-written state holding their final (post-swap) contents for that page.
+    fn repair_page(&mut self, index: usize, cache: &mut impl Cache) -> Result<()> {
+        let n = self.paired_pages;
+
+        // Reconstruct slot 0's original content for `index` from parity: every other page
+        // still holds it, either because it hasn't been swapped yet (still in slot 0) or
+        // because it already has (now sitting in slot 1).
+        let mut abuf = self.parity.clone();
+        let mut buf = vec![0u8; self.slots[0].page_size()];
+        for j in 0..n {
+            if j == index {
+                continue;
+            }
+            if j < index {
+                self.slots[1].read(j, &mut buf)?;
+            } else {
+                self.slots[0].read(j, &mut buf)?;
+            }
+            for (a, &b) in abuf.iter_mut().zip(buf.iter()) {
+                *a ^= b;
+            }
+        }
+
+        // Slot 1's original content for `index` is either still sitting in slot 1 (if slot 0
+        // hadn't been overwritten with it yet) or has already been copied into slot 0.  As in
+        // `page_done`, the tree's leaves live in the `loc.slot == 1` hash domain, so re-hashing
+        // slot 0's page must use that same domain.
+        let loc = PageLocation { slot: 1, index };
+        let slot0_ok = self.slots[0]
+            .digest(index, &loc, cache)
+            .map(|d| d == self.tree.leaf(index))
+            .unwrap_or(false);
+
+        let mut bbuf = vec![0u8; self.slots[1].page_size()];
+        if slot0_ok {
+            self.slots[0].read(index, &mut bbuf)?;
+        } else {
+            self.slots[1].read(index, &mut bbuf)?;
+            self.slots[0].erase(index, cache)?;
+            self.slots[0].write(index, &bbuf, cache)?;
+        }
+
+        self.slots[1].erase(index, cache)?;
+        self.slots[1].write(index, &abuf, cache)?;
+
+        Ok(())
     }
 
     /// Compute a final check to ensure that the given swap has completed.
-    fn final_check(&self) {
-        for sec in 0..self.slots[0].data.len() {
-            self.slots[0].data[sec].check(PageLocation {
-                slot: 1,
-                index: sec,
-            });
-            self.slots[1].data[sec].check(PageLocation {
-                slot: 0,
-                index: sec,
-            });
+    fn final_check(&self, cache: &mut impl Cache) -> Result<()> {
+        for sec in 0..self.paired_pages {
+            let d0 = self.slots[0].digest(sec, &PageLocation { slot: 1, index: sec }, cache)?;
+            if d0 != self.tree.leaf(sec) {
+                anyhow::bail!("page {} mismatch: slot 0 doesn't hold slot 1's original content", sec);
+            }
+            let d1 = self.slots[1].digest(sec, &PageLocation { slot: 0, index: sec }, cache)?;
+            if d1 != self.tree0.leaf(sec) {
+                anyhow::bail!("page {} mismatch: slot 1 doesn't hold slot 0's original content", sec);
+            }
         }
+        Ok(())
     }
 
     /// Is our position such that we should stop.
@@ -250,4 +452,3 @@ impl Status {
         }
     }
 }
-*/
@@ -0,0 +1,200 @@
+//! An `embedded-storage` adapter over `flash::Slot`.
+//!
+//! Wrapping `Slot` behind the standard `ReadNorFlash`/`NorFlash` traits lets the swap/parity
+//! logic eventually be written once against that interface and run unmodified against real
+//! hardware drivers, instead of against `Slot`'s own (private) methods.  Those traits fix their
+//! size constants at compile time, but `Slot`'s geometry is now a runtime property (see
+//! `flash::Geometry`), so this adapter only supports slots built with `flash::DEFAULT_GEOMETRY`;
+//! `SlotFlash::new` asserts that up front.  `Page::write` only ever commits a whole page at a
+//! time, so `WRITE_SIZE` and `ERASE_SIZE` are both the page size; only reads are byte-granular.
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+use crate::cache::NoCache;
+use crate::flash::{Slot, DEFAULT_GEOMETRY};
+
+/// Errors produced while driving a `Slot` through the `embedded-storage` traits.
+#[derive(Debug)]
+pub(crate) enum SlotFlashError {
+    /// The request's offset/length wasn't aligned to, or fell outside, what the operation
+    /// allows.
+    Bounds(NorFlashErrorKind),
+    /// `Slot` itself rejected the operation: for example, a read of an unwritten page, or a
+    /// write to an unerased one.
+    Page(anyhow::Error),
+}
+
+impl NorFlashError for SlotFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            SlotFlashError::Bounds(kind) => *kind,
+            SlotFlashError::Page(_) => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Drives a `Slot` through the standard `embedded-storage` `NorFlash`/`ReadNorFlash` traits.
+pub(crate) struct SlotFlash<'a> {
+    slot: &'a mut Slot,
+
+    /// The adapter doesn't need the page-state cache `Status` uses to cut down recovery-scan
+    /// reads; a `NoCache` is a correct, zero-cost stand-in so `Slot`'s cache-invalidating methods
+    /// still have somewhere to report to.
+    cache: NoCache,
+}
+
+impl<'a> SlotFlash<'a> {
+    /// Wrap `slot` for access through `embedded-storage`.  Panics if `slot` wasn't built with
+    /// `DEFAULT_GEOMETRY`, since `NorFlash`'s size constants can't vary per instance.
+    pub(crate) fn new(slot: &'a mut Slot) -> SlotFlash<'a> {
+        assert_eq!(
+            slot.geometry(),
+            DEFAULT_GEOMETRY,
+            "SlotFlash only supports slots built with DEFAULT_GEOMETRY"
+        );
+        SlotFlash {
+            slot,
+            cache: NoCache::default(),
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.slot.page_size()
+    }
+
+    /// Read page `index` without regard to its state.  An escape hatch for callers (such as a
+    /// recovery scan) that need to see whatever bytes are there even while the page is
+    /// indeterminate.
+    pub(crate) fn read_whatever(
+        &self,
+        index: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), SlotFlashError> {
+        self.slot
+            .read_whatever(index, buffer)
+            .map_err(SlotFlashError::Page)
+    }
+}
+
+impl<'a> ErrorType for SlotFlash<'a> {
+    type Error = SlotFlashError;
+}
+
+impl<'a> ReadNorFlash for SlotFlash<'a> {
+    const READ_SIZE: usize = 1;
+
+    /// Read `bytes.len()` bytes starting at `offset`.  Since a page can only be read as a whole
+    /// (`Slot::read` has no notion of a sub-page range), each page the request touches is read
+    /// into a scratch buffer and the wanted slice copied out of it.
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len()).map_err(SlotFlashError::Bounds)?;
+
+        let page_size = self.page_size();
+        let mut done = 0;
+        while done < bytes.len() {
+            let pos = offset as usize + done;
+            let index = pos / page_size;
+            let page_off = pos % page_size;
+            let take = (page_size - page_off).min(bytes.len() - done);
+
+            let mut page = vec![0u8; page_size];
+            self.slot
+                .read(index, &mut page)
+                .map_err(SlotFlashError::Page)?;
+            bytes[done..done + take].copy_from_slice(&page[page_off..page_off + take]);
+
+            done += take;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.slot.data.len() * self.page_size()
+    }
+}
+
+impl<'a> NorFlash for SlotFlash<'a> {
+    const WRITE_SIZE: usize = DEFAULT_GEOMETRY.page_size as usize;
+    const ERASE_SIZE: usize = DEFAULT_GEOMETRY.page_size as usize;
+
+    /// Erase every whole page in `[from, to)`.  Both bounds must be page-aligned, enforced by
+    /// `check_erase` since `ERASE_SIZE` is the page size.
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to).map_err(SlotFlashError::Bounds)?;
+
+        let page_size = self.page_size();
+        for index in (from as usize / page_size)..(to as usize / page_size) {
+            self.slot
+                .erase(index, &mut self.cache)
+                .map_err(SlotFlashError::Page)?;
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` starting at `offset`.  Both must be page-aligned and `bytes` a whole number
+    /// of pages, enforced by `check_write` since `WRITE_SIZE` is the page size.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len()).map_err(SlotFlashError::Bounds)?;
+
+        let page_size = self.page_size();
+        let start = offset as usize / page_size;
+        for (i, chunk) in bytes.chunks(page_size).enumerate() {
+            self.slot
+                .write(start + i, chunk, &mut self.cache)
+                .map_err(SlotFlashError::Page)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_norflash_round_trip() {
+        let mut slot = Slot::new(0, 4, DEFAULT_GEOMETRY);
+        let page_size = DEFAULT_GEOMETRY.page_size as usize;
+        let mut flash = SlotFlash::new(&mut slot);
+
+        let buf = vec![0x42u8; page_size];
+        flash.erase(0, page_size as u32).unwrap();
+        flash.write(0, &buf).unwrap();
+
+        let mut out = vec![0u8; page_size];
+        flash.read(0, &mut out).unwrap();
+        assert_eq!(out, buf);
+    }
+
+    #[test]
+    fn test_norflash_unaligned_write_rejected() {
+        let mut slot = Slot::new(0, 4, DEFAULT_GEOMETRY);
+        let page_size = DEFAULT_GEOMETRY.page_size as usize;
+        let mut flash = SlotFlash::new(&mut slot);
+
+        flash.erase(0, page_size as u32).unwrap();
+        let buf = vec![0x42u8; page_size];
+        match flash.write(1, &buf) {
+            Err(e) => assert_eq!(e.kind(), NorFlashErrorKind::NotAligned),
+            Ok(()) => panic!("expected an unaligned write to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_norflash_unwritten_read_rejected() {
+        let mut slot = Slot::new(0, 4, DEFAULT_GEOMETRY);
+        let page_size = DEFAULT_GEOMETRY.page_size as usize;
+        let mut flash = SlotFlash::new(&mut slot);
+
+        // Never erased or written: the page is still in its initial (partially-erased) state, so
+        // `Slot::read` rejects it and that rejection should surface as `Other`.
+        let mut out = vec![0u8; page_size];
+        match flash.read(0, &mut out) {
+            Err(e) => assert_eq!(e.kind(), NorFlashErrorKind::Other),
+            Ok(()) => panic!("expected a read of an unwritten page to be rejected"),
+        }
+    }
+}
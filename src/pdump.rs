@@ -54,7 +54,11 @@ impl<'a> FmtDump<'a> {
             return Ok(());
         }
 
-        writeln!(f, "{}{:06x} {:-49} |{}|", self.prefix, self.total_count, self.hex, self.ascii)?;
+        writeln!(
+            f,
+            "{}{:06x} {:-49} |{}|",
+            self.prefix, self.total_count, self.hex, self.ascii
+        )?;
         self.hex.clear();
         self.ascii.clear();
         self.total_count += 16;
@@ -150,5 +154,7 @@ impl HexDump for Vec<u8> {
 fn samples() {
     "Hello".as_bytes().dump();
     "This is a much longer string".as_bytes().dump();
-    "\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f".as_bytes().dump();
+    "\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f"
+        .as_bytes()
+        .dump();
 }